@@ -1,14 +1,15 @@
 use crate::{
-    state::{add_share_allocation, add_token_allocation, clear_all, get_all_share_allocations, get_all_token_allocations, get_token_pid, get_user_shares, get_user_tokens, INTERRUPTED_DISTRIBUTIONS, SHARE_ALLOCATIONS, TOKEN_PID},
-    types::AirdropError,
-    utils::{only_controller, token_balance, token_fee, transfer_tokens},
+    state::{add_distribution_block, add_interrupted_distribution, add_share_allocation, add_token_allocation, clear_all, get_all_distribution_blocks, get_all_interrupted_distributions, get_all_share_allocations, get_all_token_allocations, get_claim_rate, get_distribution_mode, get_token_pid, get_token_standard, get_user_distribution_block, get_user_shares, get_user_tokens, remove_share_allocation, set_claim_rate, set_distribution_mode, set_token_pid, set_token_standard},
+    types::{AirdropError, DistributionMode, TokenStandard},
+    utils::{calculate_token_per_share, only_controller, token_balance, token_fee, transfer_tokens},
 };
 
 use ic_canister::{generate_idl, query, update, Canister, Idl, PreUpdate};
 use ic_exports::{
     candid::{Nat, Principal},
-    ic_cdk::{caller, id},
+    ic_cdk::{api::time, caller, id},
 };
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
 
 #[derive(Canister)]
 pub struct Airdrop {
@@ -23,7 +24,7 @@ impl Airdrop {
     pub fn set_token_canister_id(&self, id: Principal) -> Result<(), AirdropError> {
         only_controller(caller())?;
 
-        TOKEN_PID.with(|pid| *pid.borrow_mut() = id);
+        set_token_pid(id);
 
         Ok(())
     }
@@ -40,9 +41,71 @@ impl Airdrop {
     }
 
     #[update]
-    pub fn add_share_allocations(&self, allocations: Vec<(Principal, Nat)>) -> Result<(), AirdropError> {
+    pub fn set_token_standard(&self, standard: TokenStandard) -> Result<(), AirdropError> {
         only_controller(caller())?;
 
+        set_token_standard(standard);
+
+        Ok(())
+    }
+
+    #[update]
+    pub fn validate_set_token_standard(&self, _standard: TokenStandard) -> Result<(), AirdropError> {
+        only_controller(caller())?;
+        Ok(())
+    }
+
+    #[update]
+    pub async fn set_distribution_mode(&self, mode: DistributionMode) -> Result<(), AirdropError> {
+        only_controller(caller())?;
+
+        if mode == DistributionMode::Claim {
+            // Switch the mode before the first await, so `add_share_allocations`'s
+            // Claim-mode guard already rejects new shares for the rest of this
+            // call and `lock_claim_rate` prices off a share snapshot that can't
+            // grow out from under it; roll back to the prior mode if pinning
+            // the rate fails, rather than assuming it was `Push`.
+            let previous_mode = get_distribution_mode();
+            set_distribution_mode(DistributionMode::Claim);
+
+            match lock_claim_rate().await {
+                Ok(rate) => set_claim_rate(Some(rate)),
+                Err(err) => {
+                    set_distribution_mode(previous_mode);
+                    return Err(err);
+                }
+            }
+        } else {
+            set_claim_rate(None);
+            set_distribution_mode(mode);
+        }
+
+        Ok(())
+    }
+
+    #[update]
+    pub async fn validate_set_distribution_mode(&self, mode: DistributionMode) -> Result<(), AirdropError> {
+        only_controller(caller())?;
+
+        if mode == DistributionMode::Claim {
+            lock_claim_rate().await?;
+        }
+
+        Ok(())
+    }
+
+    #[update]
+    pub fn add_share_allocations(&self, allocations: Vec<(Account, Nat)>) -> Result<(), AirdropError> {
+        only_controller(caller())?;
+
+        // `Claim` mode's payout rate is pinned against the share allocations
+        // in place when the mode was switched; adding more afterwards would
+        // let new entrants claim against a rate that was never funded for
+        // them, so allocations must be seeded before switching to `Claim`.
+        if get_distribution_mode() == DistributionMode::Claim {
+            return Err(AirdropError::ConfigurationError);
+        }
+
         allocations.iter().for_each(|allocation| {
             add_share_allocation(allocation.0, allocation.1.clone());
         });
@@ -51,11 +114,15 @@ impl Airdrop {
     }
 
     #[update]
-    pub fn validate_add_share_allocations(&self, allocations: Vec<(Principal, Nat)>) -> Result<(), AirdropError> {
+    pub fn validate_add_share_allocations(&self, allocations: Vec<(Account, Nat)>) -> Result<(), AirdropError> {
         only_controller(caller())?;
 
-        for (user, share) in allocations.iter() {
-            if *share == Nat::from(0_u8) || *user == Principal::anonymous() {
+        if get_distribution_mode() == DistributionMode::Claim {
+            return Err(AirdropError::ConfigurationError);
+        }
+
+        for (account, share) in allocations.iter() {
+            if *share == Nat::from(0_u8) || account.owner == Principal::anonymous() {
                 return Err(AirdropError::ConfigurationError)
             }
         }
@@ -82,6 +149,10 @@ impl Airdrop {
     pub async fn distribute(&self) -> Result<(), AirdropError> {
         only_controller(caller())?;
 
+        if get_distribution_mode() != DistributionMode::Push {
+            return Err(AirdropError::ConfigurationError);
+        }
+
         let total_tokens = token_balance(id()).await?;
 
         let share_allocations = get_all_share_allocations();
@@ -90,41 +161,41 @@ impl Airdrop {
             return Err(AirdropError::EmptyAllocationList);
         }
 
-        let mut shares_sum: Nat = Nat::from(0_u32);
-
-        share_allocations
-            .iter()
-            .for_each(|(_, share)| shares_sum += share.clone());
-
         let fee = token_fee().await?;
-        let total_fee = share_allocations.len() * fee;
-
-        if total_fee > total_tokens {
-            return Err(AirdropError::Unknown("Not enough token balance to cover the transfer fees.".to_string()))   
-        }
+        let token_per_share = calculate_token_per_share(total_tokens, &share_allocations, fee)?;
 
-        let token_per_share = (total_tokens - total_fee) / shares_sum;
+        // Fixed once for the whole run so that retries of the same transfer
+        // share `created_at_time` with the original call, letting the ledger
+        // deduplicate them instead of double-sending.
+        let created_at_time = time();
 
-        if token_per_share == Nat::from(0_u8) {
-            return Err(AirdropError::Unknown("Token per share is zero".to_string()));
-        }
-
-        for (user, share) in share_allocations {
+        for (account, share) in share_allocations {
             let tokens = token_per_share.clone() * share;
             let mut tries = 0;
             loop {
-                let transfer_result = transfer_tokens(user, tokens.clone()).await;
-
-                if transfer_result.is_ok() {
-                    SHARE_ALLOCATIONS.with(|allocations| allocations.borrow_mut().remove(&user));
-                    add_token_allocation(user, tokens);
-                    break;
-                } else if tries > 2 {
-                    INTERRUPTED_DISTRIBUTIONS.with(|list| list.borrow_mut().insert(user, tokens));
-                    break;
+                let transfer_result = transfer_tokens(account, tokens.clone(), created_at_time).await;
+
+                match transfer_result {
+                    Ok(block_index) => {
+                        remove_share_allocation(account);
+                        add_token_allocation(account, tokens);
+                        add_distribution_block(account, block_index);
+                        break;
+                    }
+                    Err(AirdropError::TokenCanisterError(_)) => {
+                        // The ledger rejected the transfer outright (e.g. bad fee,
+                        // insufficient funds); retrying the same call will not help.
+                        add_interrupted_distribution(account, tokens);
+                        break;
+                    }
+                    Err(_) if tries > 2 => {
+                        add_interrupted_distribution(account, tokens);
+                        break;
+                    }
+                    Err(_) => {
+                        tries += 1;
+                    }
                 }
-
-                tries += 1;
             }
         }
 
@@ -135,6 +206,10 @@ impl Airdrop {
     pub async fn validate_distribute(&self) -> Result<(), AirdropError> {
         only_controller(caller())?;
 
+        if get_distribution_mode() != DistributionMode::Push {
+            return Err(AirdropError::ConfigurationError);
+        }
+
         let total_tokens = token_balance(id()).await?;
 
         let share_allocations = get_all_share_allocations();
@@ -143,28 +218,66 @@ impl Airdrop {
             return Err(AirdropError::EmptyAllocationList);
         }
 
-        let mut shares_sum: Nat = Nat::from(0_u32);
+        let fee = token_fee().await?;
+        calculate_token_per_share(total_tokens, &share_allocations, fee)?;
 
-        share_allocations
-            .iter()
-            .for_each(|(_, share)| shares_sum += share.clone());
+        Ok(())
+    }
 
-        let fee = token_fee().await?;
-        let total_fee = share_allocations.len() * fee;
+    /// Pays out the caller's share at the `token_per_share` rate pinned when
+    /// `distribution_mode` was switched to `Claim`. The rate is read from
+    /// state rather than recomputed from the live token balance, so it
+    /// doesn't matter how many other `claim` calls are concurrently in
+    /// flight or have already settled — every claimant is paid at the same
+    /// rate regardless of interleaving.
+    #[update]
+    pub async fn claim(&self, subaccount: Option<Subaccount>) -> Result<(), AirdropError> {
+        let user = caller();
 
-        if total_fee > total_tokens {
-            return Err(AirdropError::Unknown("Not enough token balance to cover the transfer fees.".to_string()))   
+        if user == Principal::anonymous() {
+            return Err(AirdropError::Unauthorized);
         }
 
-        let token_per_share = (total_tokens - total_fee) / shares_sum;
+        if get_distribution_mode() != DistributionMode::Claim {
+            return Err(AirdropError::ConfigurationError);
+        }
 
-        if token_per_share == Nat::from(0_u8) {
-            return Err(AirdropError::Unknown("Token per share is zero".to_string()));
+        let account = Account { owner: user, subaccount };
+
+        let share = get_user_shares(account).ok_or(AirdropError::NoAllocationFound)?;
+        let token_per_share = get_claim_rate().ok_or(AirdropError::ConfigurationError)?;
+
+        // Remove the allocation before the first await so a concurrent or
+        // retried `claim` call for this account can't still see it and be
+        // paid out twice; restore it below if the transfer doesn't go through.
+        remove_share_allocation(account);
+
+        if let Err(err) = settle_claim(account, share.clone(), token_per_share).await {
+            add_share_allocation(account, share);
+            return Err(err);
         }
 
         Ok(())
     }
 
+    #[update]
+    pub fn validate_claim(&self, subaccount: Option<Subaccount>) -> Result<(), AirdropError> {
+        let user = caller();
+
+        if user == Principal::anonymous() {
+            return Err(AirdropError::Unauthorized);
+        }
+
+        if get_distribution_mode() != DistributionMode::Claim {
+            return Err(AirdropError::ConfigurationError);
+        }
+
+        get_user_shares(Account { owner: user, subaccount }).ok_or(AirdropError::NoAllocationFound)?;
+        get_claim_rate().ok_or(AirdropError::ConfigurationError)?;
+
+        Ok(())
+    }
+
     #[query]
     pub fn get_token_canister_id(&self) -> Option<Principal> {
         let id = get_token_pid();
@@ -175,17 +288,32 @@ impl Airdrop {
     }
 
     #[query]
-    pub fn get_user_share_allocation(&self, user: Principal) -> Option<Nat> {
-        get_user_shares(user)
+    pub fn get_distribution_mode(&self) -> DistributionMode {
+        get_distribution_mode()
+    }
+
+    #[query]
+    pub fn get_token_standard(&self) -> TokenStandard {
+        get_token_standard()
     }
 
     #[query]
-    pub fn get_user_token_allocation(&self, user: Principal) -> Option<Nat> {
-        get_user_tokens(user)
+    pub fn get_user_share_allocation(&self, user: Principal, subaccount: Option<Subaccount>) -> Option<Nat> {
+        get_user_shares(Account { owner: user, subaccount })
     }
 
     #[query]
-    pub fn get_shares_list(&self, start_index: u64) -> Vec<(Principal, Nat)> {
+    pub fn get_user_token_allocation(&self, user: Principal, subaccount: Option<Subaccount>) -> Option<Nat> {
+        get_user_tokens(Account { owner: user, subaccount })
+    }
+
+    #[query]
+    pub fn get_distribution_block(&self, user: Principal, subaccount: Option<Subaccount>) -> Option<Nat> {
+        get_user_distribution_block(Account { owner: user, subaccount })
+    }
+
+    #[query]
+    pub fn get_shares_list(&self, start_index: u64) -> Vec<(Account, Nat)> {
         let allocations = get_all_share_allocations();
         let start_index = start_index as usize;
         let end_index = usize::min(start_index + 100, allocations.len());
@@ -198,13 +326,13 @@ impl Airdrop {
     }
 
     #[query]
-    pub fn get_interrupted_distributions(&self,) -> Vec<(Principal, Nat)> {
-        INTERRUPTED_DISTRIBUTIONS.with(|allocations| allocations.borrow().clone().into_iter().collect())
+    pub fn get_interrupted_distributions(&self,) -> Vec<(Account, Nat)> {
+        get_all_interrupted_distributions()
     }
 
 
     #[query]
-    pub fn get_tokens_list(&self, start_index: u64) -> Vec<(Principal, Nat)> {
+    pub fn get_tokens_list(&self, start_index: u64) -> Vec<(Account, Nat)> {
         let allocations = get_all_token_allocations();
         let start_index = start_index as usize;
         let end_index = usize::min(start_index + 100, allocations.len());
@@ -216,7 +344,55 @@ impl Airdrop {
         allocations[start_index..end_index].to_vec()
     }
 
+    #[query]
+    pub fn get_distribution_blocks(&self, start_index: u64) -> Vec<(Account, Nat)> {
+        let blocks = get_all_distribution_blocks();
+        let start_index = start_index as usize;
+        let end_index = usize::min(start_index + 100, blocks.len());
+
+        if start_index >= blocks.len() {
+            return vec![];
+        }
+
+        blocks[start_index..end_index].to_vec()
+    }
+
     pub fn idl() -> Idl {
         generate_idl!()
     }
 }
+
+/// Computes `account`'s token payout from its `share` at the pinned
+/// `token_per_share` claim rate and transfers it, recording the resulting
+/// token allocation and distribution block on success. Taking the rate as
+/// an already-pinned value (rather than recomputing it here from the live
+/// token balance and remaining share allocations) is what keeps concurrent
+/// `claim` calls from different accounts from paying out at different
+/// rates depending on how many other claims have settled in the meantime.
+async fn settle_claim(account: Account, share: Nat, token_per_share: Nat) -> Result<(), AirdropError> {
+    let tokens = token_per_share * share;
+
+    let block_index = transfer_tokens(account, tokens.clone(), time()).await?;
+
+    add_token_allocation(account, tokens);
+    add_distribution_block(account, block_index);
+
+    Ok(())
+}
+
+/// Computes the `token_per_share` rate for a `Claim` round from the current
+/// token balance and outstanding share allocations. Called once, when
+/// `distribution_mode` is switched to `Claim`, so the rate can be pinned into
+/// stable state instead of being recomputed live on every `claim()` call.
+async fn lock_claim_rate() -> Result<Nat, AirdropError> {
+    let total_tokens = token_balance(id()).await?;
+
+    let share_allocations = get_all_share_allocations();
+
+    if share_allocations.len() < 1 {
+        return Err(AirdropError::EmptyAllocationList);
+    }
+
+    let fee = token_fee().await?;
+    calculate_token_per_share(total_tokens, &share_allocations, fee)
+}
@@ -1,54 +1,290 @@
-use std::{cell::RefCell, collections::HashMap};
+use std::{borrow::Cow, cell::RefCell};
 
-use candid::{Nat, Principal};
+use candid::{CandidType, Nat, Principal};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    storable::Bound,
+    Cell as StableCell, DefaultMemoryImpl, StableBTreeMap, Storable,
+};
+use icrc_ledger_types::icrc1::account::Account;
+use serde::Deserialize;
+
+use crate::types::{DistributionMode, TokenStandard};
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Candid-encoded `Account`, so it can key a stable `BTreeMap`
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct AccountKey(Account);
+
+impl Storable for AccountKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self.0).expect("Failed to encode Account"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        AccountKey(candid::decode_one(&bytes).expect("Failed to decode Account"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Candid-encoded `Nat`, so it can be the value of a stable `BTreeMap`
+#[derive(Clone)]
+struct NatValue(Nat);
+
+impl Storable for NatValue {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(&self.0).expect("Failed to encode Nat"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        NatValue(candid::decode_one(&bytes).expect("Failed to decode Nat"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The token canister pid, token standard, distribution mode and pinned claim
+/// rate, candid-encoded together so they can live in a single stable `Cell`
+#[derive(Clone, CandidType, Deserialize)]
+struct Config {
+    token_pid: Principal,
+    token_standard: TokenStandard,
+    distribution_mode: DistributionMode,
+    /// Token amount owed per share while in `Claim` mode, pinned once when
+    /// the mode is switched to `Claim` so every `claim()` call afterwards
+    /// pays out at the same locked-in rate instead of recomputing it from
+    /// the live token balance and remaining share allocations on every call
+    claim_rate: Option<Nat>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            token_pid: Principal::anonymous(),
+            token_standard: TokenStandard::Icrc1,
+            distribution_mode: DistributionMode::Push,
+            claim_rate: None,
+        }
+    }
+}
+
+impl Storable for Config {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode Config"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode Config")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
 
 thread_local! {
-    /// Token canister's principal ID
-    pub static TOKEN_PID: RefCell<Principal> = RefCell::new(Principal::anonymous());
-    /// HashMap of all participants and their receiving amount
-    pub static TOKEN_ALLOCATIONS: RefCell<HashMap<Principal, Nat>> = RefCell::new(HashMap::new());
-    /// HashMap of all participants and their shares
-    pub static SHARE_ALLOCATIONS: RefCell<HashMap<Principal, Nat>> = RefCell::new(HashMap::new());
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    /// Stable cell holding the token canister pid, token standard and distribution
+    /// mode, so a canister upgrade doesn't need a `pre_upgrade`/`post_upgrade` copy
+    static CONFIG: RefCell<StableCell<Config, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|manager| manager.borrow().get(MemoryId::new(0))),
+            Config::default(),
+        )
+        .expect("Failed to init config cell"),
+    );
+
+    /// Stable map of all participant accounts and their receiving amount, so
+    /// it survives a canister upgrade without a `pre_upgrade` copy
+    static TOKEN_ALLOCATIONS: RefCell<StableBTreeMap<AccountKey, NatValue, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|manager| manager.borrow().get(MemoryId::new(1)))),
+    );
+    /// Stable map of all participant accounts and their shares
+    static SHARE_ALLOCATIONS: RefCell<StableBTreeMap<AccountKey, NatValue, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|manager| manager.borrow().get(MemoryId::new(2)))),
+    );
+    /// Stable map of all participant accounts and the ledger block index their transfer settled at
+    static DISTRIBUTION_BLOCKS: RefCell<StableBTreeMap<AccountKey, NatValue, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|manager| manager.borrow().get(MemoryId::new(3)))),
+    );
+    /// Stable map of participant accounts whose push distribution failed, either
+    /// because the ledger rejected the transfer or because it ran out of retries
+    static INTERRUPTED_DISTRIBUTIONS: RefCell<StableBTreeMap<AccountKey, NatValue, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|manager| manager.borrow().get(MemoryId::new(4)))),
+    );
 }
 
-/// Clears the token canister pid, token allocations and share allocations
+/// Clears the token canister pid, token standard, token allocations, share allocations,
+/// distribution blocks, interrupted distributions and resets the distribution mode back to `Push`
 pub fn clear_all() {
-    TOKEN_PID.with(|pid| *pid.borrow_mut() = Principal::anonymous());
-    TOKEN_ALLOCATIONS.with(|allocations| allocations.borrow_mut().clear());
-    SHARE_ALLOCATIONS.with(|allocations| allocations.borrow_mut().clear());
+    CONFIG.with(|config| {
+        config
+            .borrow_mut()
+            .set(Config::default())
+            .expect("Failed to reset config cell")
+    });
+    clear_stable_map(&TOKEN_ALLOCATIONS);
+    clear_stable_map(&SHARE_ALLOCATIONS);
+    clear_stable_map(&DISTRIBUTION_BLOCKS);
+    clear_stable_map(&INTERRUPTED_DISTRIBUTIONS);
+}
+
+fn clear_stable_map(
+    map: &'static std::thread::LocalKey<RefCell<StableBTreeMap<AccountKey, NatValue, Memory>>>,
+) {
+    map.with(|map| {
+        let keys: Vec<AccountKey> = map.borrow().iter().map(|(key, _)| key).collect();
+        let mut map = map.borrow_mut();
+        for key in keys {
+            map.remove(&key);
+        }
+    });
+}
+
+/// Returns the currently configured token standard
+pub fn get_token_standard() -> TokenStandard {
+    CONFIG.with(|config| config.borrow().get().token_standard)
+}
+
+/// Sets the token standard
+pub fn set_token_standard(standard: TokenStandard) {
+    update_config(|config| config.token_standard = standard);
 }
 
 /// Returns the token's principal ID
 pub fn get_token_pid() -> Principal {
-    TOKEN_PID.with(|pid| pid.borrow().clone())
+    CONFIG.with(|config| config.borrow().get().token_pid.clone())
+}
+
+/// Sets the token canister's principal ID
+pub fn set_token_pid(pid: Principal) {
+    update_config(|config| config.token_pid = pid);
 }
 
-/// Returns the amount of shares allocated to `user`
-pub fn get_user_shares(user: Principal) -> Option<Nat> {
-    SHARE_ALLOCATIONS.with(|allocations| allocations.borrow().get(&user).cloned())
+fn update_config(update: impl FnOnce(&mut Config)) {
+    CONFIG.with(|config| {
+        let mut current = config.borrow().get().clone();
+        update(&mut current);
+        config.borrow_mut().set(current).expect("Failed to update config cell");
+    });
 }
 
-/// Returns the vector of all users and their share allocations
-pub fn get_all_share_allocations() -> Vec<(Principal, Nat)> {
-    SHARE_ALLOCATIONS.with(|allocations| allocations.borrow().clone().into_iter().collect())
+/// Returns the amount of shares allocated to `account`
+pub fn get_user_shares(account: Account) -> Option<Nat> {
+    SHARE_ALLOCATIONS.with(|allocations| allocations.borrow().get(&AccountKey(account)).map(|value| value.0))
+}
+
+/// Returns the vector of all accounts and their share allocations
+pub fn get_all_share_allocations() -> Vec<(Account, Nat)> {
+    SHARE_ALLOCATIONS.with(|allocations| {
+        allocations
+            .borrow()
+            .iter()
+            .map(|(key, value)| (key.0, value.0))
+            .collect()
+    })
 }
 
 /// Add a share allocation
-pub fn add_share_allocation(user: Principal, amount: Nat) {
-    SHARE_ALLOCATIONS.with(|allocations| allocations.borrow_mut().insert(user, amount));
+pub fn add_share_allocation(account: Account, amount: Nat) {
+    SHARE_ALLOCATIONS.with(|allocations| {
+        allocations.borrow_mut().insert(AccountKey(account), NatValue(amount))
+    });
+}
+
+/// Removes `account`'s share allocation, e.g. once it has been distributed or claimed
+pub fn remove_share_allocation(account: Account) {
+    SHARE_ALLOCATIONS.with(|allocations| {
+        allocations.borrow_mut().remove(&AccountKey(account));
+    });
 }
 
-/// Returns the amount of tokens allocated to `user`
-pub fn get_user_tokens(user: Principal) -> Option<Nat> {
-    TOKEN_ALLOCATIONS.with(|allocations| allocations.borrow().get(&user).cloned())
+/// Returns the amount of tokens allocated to `account`
+pub fn get_user_tokens(account: Account) -> Option<Nat> {
+    TOKEN_ALLOCATIONS.with(|allocations| allocations.borrow().get(&AccountKey(account)).map(|value| value.0))
 }
 
-/// Returns the vector of all users and their token allocations
-pub fn get_all_token_allocations() -> Vec<(Principal, Nat)> {
-    TOKEN_ALLOCATIONS.with(|allocations| allocations.borrow().clone().into_iter().collect())
+/// Returns the vector of all accounts and their token allocations
+pub fn get_all_token_allocations() -> Vec<(Account, Nat)> {
+    TOKEN_ALLOCATIONS.with(|allocations| {
+        allocations
+            .borrow()
+            .iter()
+            .map(|(key, value)| (key.0, value.0))
+            .collect()
+    })
 }
 
 /// Add a token allocation
-pub fn add_token_allocation(user: Principal, amount: Nat) {
-    TOKEN_ALLOCATIONS.with(|allocations| allocations.borrow_mut().insert(user, amount));
-}
\ No newline at end of file
+pub fn add_token_allocation(account: Account, amount: Nat) {
+    TOKEN_ALLOCATIONS.with(|allocations| {
+        allocations.borrow_mut().insert(AccountKey(account), NatValue(amount))
+    });
+}
+
+/// Returns the ledger block index `account`'s transfer settled at
+pub fn get_user_distribution_block(account: Account) -> Option<Nat> {
+    DISTRIBUTION_BLOCKS.with(|blocks| blocks.borrow().get(&AccountKey(account)).map(|value| value.0))
+}
+
+/// Returns the vector of all accounts and the ledger block index their transfer settled at
+pub fn get_all_distribution_blocks() -> Vec<(Account, Nat)> {
+    DISTRIBUTION_BLOCKS.with(|blocks| {
+        blocks
+            .borrow()
+            .iter()
+            .map(|(key, value)| (key.0, value.0))
+            .collect()
+    })
+}
+
+/// Records the ledger block index `account`'s transfer settled at
+pub fn add_distribution_block(account: Account, block_index: Nat) {
+    DISTRIBUTION_BLOCKS.with(|blocks| {
+        blocks.borrow_mut().insert(AccountKey(account), NatValue(block_index))
+    });
+}
+
+/// Records that `account`'s push distribution failed (the ledger rejected the
+/// transfer or it ran out of retries), storing the
+/// amount it was owed so the controller can retry or refund it later
+pub fn add_interrupted_distribution(account: Account, amount: Nat) {
+    INTERRUPTED_DISTRIBUTIONS.with(|list| {
+        list.borrow_mut().insert(AccountKey(account), NatValue(amount))
+    });
+}
+
+/// Returns the vector of all accounts whose push distribution failed (the
+/// ledger rejected the transfer or it ran out of retries)
+pub fn get_all_interrupted_distributions() -> Vec<(Account, Nat)> {
+    INTERRUPTED_DISTRIBUTIONS.with(|list| {
+        list.borrow()
+            .iter()
+            .map(|(key, value)| (key.0, value.0))
+            .collect()
+    })
+}
+
+/// Returns the currently configured distribution mode
+pub fn get_distribution_mode() -> DistributionMode {
+    CONFIG.with(|config| config.borrow().get().distribution_mode)
+}
+
+/// Sets the distribution mode
+pub fn set_distribution_mode(mode: DistributionMode) {
+    update_config(|config| config.distribution_mode = mode);
+}
+
+/// Returns the token amount owed per share pinned for the current `Claim`
+/// round, or `None` if no rate has been locked in yet
+pub fn get_claim_rate() -> Option<Nat> {
+    CONFIG.with(|config| config.borrow().get().claim_rate.clone())
+}
+
+/// Pins (or clears) the token amount owed per share for the current `Claim`
+/// round
+pub fn set_claim_rate(rate: Option<Nat>) {
+    update_config(move |config| config.claim_rate = rate);
+}
@@ -7,5 +7,24 @@ pub enum AirdropError {
     TokenCanisterError(String),
     Unauthorized,
     EmptyAllocationList,
-    ConfigurationError
+    ConfigurationError,
+    NoAllocationFound
+}
+
+/// The strategy used to get tokens to recipients
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DistributionMode {
+    /// The controller iterates over all share allocations and transfers tokens to each recipient
+    Push,
+    /// Recipients call `claim` themselves to transfer their own computed share
+    Claim,
+}
+
+/// The ledger interface the configured token canister implements
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TokenStandard {
+    /// An ICRC-1 compliant ledger, addressed by `Account { owner, subaccount }`
+    Icrc1,
+    /// The native ICP ledger, addressed by a 32-byte `AccountIdentifier`
+    Icp,
 }
\ No newline at end of file
@@ -1,8 +1,13 @@
-use candid::{Nat, Principal};
+use async_trait::async_trait;
+use candid::{CandidType, Nat, Principal};
 use ic_exports::{ic_cdk::{api::is_controller, call}, ic_kit::CallResult};
-use icrc_ledger_types::icrc1::{account::Account, transfer::{TransferArg, TransferError}};
+use icrc_ledger_types::icrc1::{account::Account, transfer::{Memo, TransferArg, TransferError}};
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha224};
+use num_traits::ToPrimitive;
 
-use crate::{state::get_token_pid, types::AirdropError};
+use crate::{state::{get_token_pid, get_token_standard}, types::{AirdropError, TokenStandard}};
 
 /// Returns error if `caller` is not a controller of the canister
 pub fn only_controller(caller: Principal) -> Result<(), AirdropError> {
@@ -12,34 +17,26 @@ pub fn only_controller(caller: Principal) -> Result<(), AirdropError> {
     Ok(())
 }
 
-/// Transfers `amount` tokens to `receiver_pid`
-pub async fn transfer_tokens(receiver_pid: Principal, amount: Nat) -> Result<(), AirdropError> {
+/// Transfers `amount` tokens to `receiver`, returning the ledger block
+/// index the transfer settled at.
+///
+/// `created_at_time` should be fixed once per distribution run (rather than
+/// resampled on every retry) so that a retry after a lost response shares the
+/// same `from`/`to`/`amount`/`fee`/`memo`/`created_at_time` tuple as the
+/// original call. The ledger then deduplicates it and returns a "duplicate"
+/// error instead of executing a second transfer, which both adapters below
+/// treat as success and report the original block index.
+pub async fn transfer_tokens(
+    receiver: Account,
+    amount: Nat,
+    created_at_time: u64,
+) -> Result<Nat, AirdropError> {
     let token_canister = get_token_pid();
     not_anonymous(&token_canister)?;
 
-    let transfer_args = TransferArg {
-        from_subaccount: None,
-        to: Account {
-            owner: receiver_pid,
-            subaccount: None,
-        },
-        fee: None,
-        created_at_time: None,
-        memo: None,
-        amount,
-    };
-
-    let call_response = call(token_canister, "icrc1_transfer", (transfer_args, )).await;
-
-    match handle_intercanister_call::<Result<Nat, TransferError>>(call_response)? {
-        Err(err) => Err(AirdropError::TokenCanisterError(format!(
-            "Error occured on token transfer: {:#?}",
-            err
-        ))),
-        _ => Ok(()),
-    }?;
-
-    Ok(())
+    token_adapter(token_canister)
+        .transfer(receiver, amount, created_at_time)
+        .await
 }
 
 /// Returns the token's transfer fee
@@ -47,11 +44,7 @@ pub async fn token_fee() -> Result<Nat, AirdropError> {
     let token_canister = get_token_pid();
     not_anonymous(&token_canister)?;
 
-    let call_response = call(token_canister, "icrc1_fee", ()).await;
-
-    let fee = handle_intercanister_call::<Nat>(call_response)?;
-
-    Ok(fee)
+    token_adapter(token_canister).fee().await
 }
 
 /// Returns `user`'s token balance
@@ -59,16 +52,227 @@ pub async fn token_balance(user: Principal) -> Result<Nat, AirdropError> {
     let token_canister = get_token_pid();
     not_anonymous(&token_canister)?;
 
-    let account = Account {
-        owner: user,
-        subaccount: None,
-    };
+    token_adapter(token_canister).balance_of(user).await
+}
+
+/// Builds the adapter matching the currently configured token standard
+fn token_adapter(token_canister: Principal) -> Box<dyn TokenAdapter> {
+    match get_token_standard() {
+        TokenStandard::Icrc1 => Box::new(Icrc1Adapter { token_canister }),
+        TokenStandard::Icp => Box::new(IcpAdapter { token_canister }),
+    }
+}
+
+/// Dispatches the ledger calls a distribution needs over the standard the
+/// configured token canister actually implements, so `distribute`/`claim`
+/// don't need to know whether they're talking to an ICRC-1 ledger or the
+/// native ICP ledger.
+#[async_trait]
+trait TokenAdapter {
+    async fn transfer(&self, receiver: Account, amount: Nat, created_at_time: u64) -> Result<Nat, AirdropError>;
+    async fn fee(&self) -> Result<Nat, AirdropError>;
+    async fn balance_of(&self, owner: Principal) -> Result<Nat, AirdropError>;
+}
+
+/// Adapter for ICRC-1 compliant ledgers, addressed by `Account { owner, subaccount }`
+struct Icrc1Adapter {
+    token_canister: Principal,
+}
+
+#[async_trait]
+impl TokenAdapter for Icrc1Adapter {
+    async fn transfer(&self, receiver: Account, amount: Nat, created_at_time: u64) -> Result<Nat, AirdropError> {
+        let memo = memo_for(&receiver);
+
+        let transfer_args = TransferArg {
+            from_subaccount: None,
+            to: receiver,
+            fee: None,
+            created_at_time: Some(created_at_time),
+            memo: Some(memo),
+            amount,
+        };
+
+        let call_response = call(self.token_canister, "icrc1_transfer", (transfer_args, )).await;
+
+        match handle_intercanister_call::<Result<Nat, TransferError>>(call_response)? {
+            Ok(block_index) => Ok(block_index),
+            Err(TransferError::Duplicate { duplicate_of }) => Ok(duplicate_of),
+            Err(err) => Err(AirdropError::TokenCanisterError(format!(
+                "Error occured on token transfer: {:#?}",
+                err
+            ))),
+        }
+    }
+
+    async fn fee(&self) -> Result<Nat, AirdropError> {
+        let call_response = call(self.token_canister, "icrc1_fee", ()).await;
+
+        handle_intercanister_call::<Nat>(call_response)
+    }
+
+    async fn balance_of(&self, owner: Principal) -> Result<Nat, AirdropError> {
+        let account = Account {
+            owner,
+            subaccount: None,
+        };
+
+        let call_response = call(self.token_canister, "icrc1_balance_of", (account,)).await;
+
+        handle_intercanister_call::<Nat>(call_response)
+    }
+}
+
+/// Builds a deterministic memo for `receiver` so the same recipient principal
+/// always produces the same memo bytes within a distribution run. Keyed on the
+/// owner only: most deployed ICRC-1 ledgers cap `memo` at 32 bytes, and folding
+/// in a subaccount on top of the principal can exceed that, so the subaccount
+/// is left for `to: Account` in the transfer args to disambiguate instead.
+fn memo_for(receiver: &Account) -> Memo {
+    Memo(ByteBuf::from(receiver.owner.as_slice().to_vec()))
+}
+
+/// Adapter for the native ICP ledger, addressed by a 32-byte `AccountIdentifier`
+struct IcpAdapter {
+    token_canister: Principal,
+}
+
+/// The ICP ledger's fixed protocol transfer fee, in e8s
+const ICP_TRANSFER_FEE_E8S: u64 = 10_000;
+
+#[async_trait]
+impl TokenAdapter for IcpAdapter {
+    async fn transfer(&self, receiver: Account, amount: Nat, created_at_time: u64) -> Result<Nat, AirdropError> {
+        let transfer_args = IcpTransferArgs {
+            memo: 0,
+            amount: IcpTokens { e8s: nat_to_e8s(&amount)? },
+            fee: IcpTokens { e8s: ICP_TRANSFER_FEE_E8S },
+            from_subaccount: None,
+            to: account_identifier(receiver.owner, receiver.subaccount),
+            created_at_time: Some(IcpTimestamp { timestamp_nanos: created_at_time }),
+        };
+
+        let call_response = call(self.token_canister, "transfer", (transfer_args,)).await;
 
-    let call_response = call(token_canister, "icrc1_balance_of", (account,)).await;
+        match handle_intercanister_call::<Result<u64, IcpTransferError>>(call_response)? {
+            Ok(block_index) => Ok(Nat::from(block_index)),
+            Err(IcpTransferError::TxDuplicate { duplicate_of }) => Ok(Nat::from(duplicate_of)),
+            Err(err) => Err(AirdropError::TokenCanisterError(format!(
+                "Error occured on token transfer: {:#?}",
+                err
+            ))),
+        }
+    }
+
+    async fn fee(&self) -> Result<Nat, AirdropError> {
+        Ok(Nat::from(ICP_TRANSFER_FEE_E8S))
+    }
+
+    async fn balance_of(&self, owner: Principal) -> Result<Nat, AirdropError> {
+        let args = IcpAccountBalanceArgs {
+            account: account_identifier(owner, None),
+        };
+
+        let call_response = call(self.token_canister, "account_balance", (args,)).await;
+
+        let balance = handle_intercanister_call::<IcpTokens>(call_response)?;
+
+        Ok(Nat::from(balance.e8s))
+    }
+}
+
+/// Derives the 32-byte ICP ledger `AccountIdentifier` for `owner`/`subaccount`: the
+/// SHA-224 hash of the `\x0Aaccount-id` domain separator, the owner's principal bytes
+/// and the subaccount, prefixed with a 4-byte big-endian CRC32 checksum of that hash.
+fn account_identifier(owner: Principal, subaccount: Option<[u8; 32]>) -> Vec<u8> {
+    let mut hasher = Sha224::new();
+    hasher.update(b"\x0Aaccount-id");
+    hasher.update(owner.as_slice());
+    hasher.update(subaccount.unwrap_or([0u8; 32]));
+    let hash = hasher.finalize();
+
+    let checksum = crc32fast::hash(&hash).to_be_bytes();
+
+    let mut account_identifier = Vec::with_capacity(4 + hash.len());
+    account_identifier.extend_from_slice(&checksum);
+    account_identifier.extend_from_slice(&hash);
+    account_identifier
+}
+
+/// Converts a `Nat` token amount into e8s, the unit the ICP ledger's `ICPTs` uses
+fn nat_to_e8s(amount: &Nat) -> Result<u64, AirdropError> {
+    amount
+        .0
+        .to_u64()
+        .ok_or_else(|| AirdropError::Unknown("Token amount does not fit into an ICP ledger e8s amount".to_string()))
+}
+
+#[derive(CandidType)]
+struct IcpTransferArgs {
+    memo: u64,
+    amount: IcpTokens,
+    fee: IcpTokens,
+    from_subaccount: Option<[u8; 32]>,
+    to: Vec<u8>,
+    created_at_time: Option<IcpTimestamp>,
+}
 
-    let fee = handle_intercanister_call::<Nat>(call_response)?;
+#[derive(CandidType)]
+struct IcpAccountBalanceArgs {
+    account: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy)]
+struct IcpTokens {
+    e8s: u64,
+}
+
+#[derive(CandidType)]
+struct IcpTimestamp {
+    timestamp_nanos: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+enum IcpTransferError {
+    BadFee { expected_fee: IcpTokens },
+    InsufficientFunds { balance: IcpTokens },
+    TxTooOld { allowed_window_nanos: u64 },
+    TxCreatedInFuture,
+    TxDuplicate { duplicate_of: u64 },
+}
+
+/// Computes the token amount owed per share: the canister's token balance,
+/// minus the ledger fee reserved for every outstanding transfer, split across
+/// all outstanding shares. Shared by the push (`distribute`) and pull
+/// (`claim`) distribution paths so both pay out at the same rate.
+pub fn calculate_token_per_share(
+    total_tokens: Nat,
+    share_allocations: &[(Account, Nat)],
+    fee: Nat,
+) -> Result<Nat, AirdropError> {
+    if share_allocations.is_empty() {
+        return Err(AirdropError::EmptyAllocationList);
+    }
+
+    let mut shares_sum: Nat = Nat::from(0_u32);
+
+    share_allocations
+        .iter()
+        .for_each(|(_, share)| shares_sum += share.clone());
+
+    let total_fee = share_allocations.len() * fee;
+
+    if total_fee > total_tokens {
+        return Err(AirdropError::Unknown("Not enough token balance to cover the transfer fees.".to_string()));
+    }
+
+    let token_per_share = (total_tokens - total_fee) / shares_sum;
+
+    if token_per_share == Nat::from(0_u8) {
+        return Err(AirdropError::Unknown("Token per share is zero".to_string()));
+    }
 
-    Ok(fee)
+    Ok(token_per_share)
 }
 
 pub fn not_anonymous(id: &Principal) -> Result<(), AirdropError> {